@@ -27,10 +27,20 @@
 #![deny(missing_docs)]
 #![deny(warnings)]
 
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::process::Command;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+// Process-wide cache keyed by the fully-resolved invocation (rustc path +
+// target). Populated by `Cfg::of_cached` and cleared by `Cfg::clear_cache`.
+static CACHE: Mutex<Option<HashMap<String, Cfg>>> = Mutex::new(None);
 
 /// The result of parsing the output of `rustc --print cfg`
+#[derive(Clone)]
 #[cfg_attr(test, derive(Debug))]
 pub struct Cfg {
     /// Equivalent to `cfg(target_os = "..")`
@@ -51,6 +61,13 @@ pub struct Cfg {
     pub target_has_atomic: Vec<String>,
     /// Equivalent to `cfg(target_feature = "..")`
     pub target_feature: Vec<String>,
+    /// Every standalone identifier rustc reports, e.g. `unix`, `debug_assertions`
+    /// or `proc_macro` — the lines of `rustc --print cfg` that carry no `=`.
+    pub atoms: Vec<String>,
+    /// Every `key = "value"` pair rustc reports, including keys this crate has no
+    /// named field for (e.g. `panic`, `target_abi`). Multi-valued keys such as
+    /// `target_feature` keep all of their values.
+    pub key_values: BTreeMap<String, Vec<String>>,
     _extensible: (),
 }
 
@@ -63,18 +80,92 @@ impl Cfg {
     /// Cargo targets, i.e. binaries, `[[bin]]` and a library `[lib]` define in
     /// a package's manifest (Cargo.toml).
     pub fn of(target: &str) -> Result<Cfg, failure::Error> {
-        // NOTE Cargo passes RUSTC to build scripts, prefer that over plain `rustc`.
-        let output = Command::new(env::var("RUSTC").as_ref().map(|s| &**s).unwrap_or("rustc"))
-            .arg("--target")
-            .arg(target)
-            .args(&["--print", "cfg"])
-            .output()?;
+        Cfg::builder().target(target).run()
+    }
 
-        if !output.status.success() {
-            return Err(failure::err_msg(String::from_utf8(output.stderr)?));
+    /// Like [`Cfg::of`] but memoizes the result in a process-wide cache keyed by
+    /// the resolved `rustc` path and the target.
+    ///
+    /// A second call for the same target returns a clone of the cached value
+    /// without re-spawning `rustc`. Use [`Cfg::clear_cache`] to drop the cache.
+    pub fn of_cached(target: &str) -> Result<Cfg, failure::Error> {
+        let rustc = env::var_os("RUSTC")
+            .unwrap_or_else(|| OsString::from("rustc"))
+            .to_string_lossy()
+            .into_owned();
+        let key = format!("{}\u{0}{}", rustc, target);
+
+        if let Some(ref map) = *CACHE.lock().unwrap() {
+            if let Some(cfg) = map.get(&key) {
+                return Ok(cfg.clone());
+            }
+        }
+
+        let cfg = Cfg::of(target)?;
+        CACHE
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .insert(key, cfg.clone());
+        Ok(cfg)
+    }
+
+    /// Empties the process-wide cache populated by [`Cfg::of_cached`].
+    pub fn clear_cache() {
+        if let Some(ref mut map) = *CACHE.lock().unwrap() {
+            map.clear();
+        }
+    }
+
+    /// Returns a [`CfgBuilder`] to customize the `rustc` path, extra flags,
+    /// environment and target before querying the cfgs.
+    pub fn builder() -> CfgBuilder {
+        CfgBuilder::new()
+    }
+
+    /// Queries the output filename prefix/suffix for every crate type on
+    /// `target`.
+    ///
+    /// Runs `rustc --target <target> --crate-type <ct> --print file-names -` for
+    /// each [`CrateType`] and splits the emitted filename around the crate name
+    /// into a `(prefix, suffix)` pair (e.g. `("lib", ".so")` for a `cdylib`).
+    /// Crate types the target does not support map to `None`.
+    pub fn outputs(target: &str) -> Result<Outputs, failure::Error> {
+        let mut map = HashMap::new();
+        for &crate_type in CRATE_TYPES {
+            map.insert(crate_type, file_names(target, crate_type)?);
+        }
+        Ok(Outputs { map })
+    }
+
+    /// Builds the `cargo::rustc-check-cfg` declarations covering every atom and
+    /// key/value pair this target reports.
+    ///
+    /// A build script that sets matching `--cfg` flags can print these lines to
+    /// whitelist them and silence the `unexpected_cfgs` lint. Bare atoms become
+    /// `cfg(name)` and each key groups all of its values into a single
+    /// `cfg(key, values("a", "b"))` statement.
+    pub fn check_cfg_lines(&self) -> Vec<String> {
+        let mut lines = vec![];
+
+        for atom in &self.atoms {
+            lines.push(format!("cargo::rustc-check-cfg=cfg({})", atom));
+        }
+
+        for (key, values) in &self.key_values {
+            let values = values
+                .iter()
+                .map(|value| format!("\"{}\"", value))
+                .collect::<Vec<_>>()
+                .join(", ");
+            lines.push(format!("cargo::rustc-check-cfg=cfg({}, values({}))", key, values));
         }
 
-        let spec = String::from_utf8(output.stdout)?;
+        lines
+    }
+
+    /// Parses the textual output of `rustc --print cfg`.
+    fn parse(spec: &str) -> Result<Cfg, failure::Error> {
         let mut target_os = None;
         let mut target_family = None;
         let mut target_arch = None;
@@ -84,27 +175,31 @@ impl Cfg {
         let mut target_vendor = None;
         let mut target_has_atomic = vec![];
         let mut target_feature = vec![];
+        let mut atoms = vec![];
+        let mut key_values: BTreeMap<String, Vec<String>> = BTreeMap::new();
 
         for entry in spec.lines() {
-            let mut parts = entry.split('=');
-
-            if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
-                match key {
-                    "target_os" => target_os = Some(value.trim_matches('"').to_string()),
-                    "target_family" => target_family = Some(value.trim_matches('"').to_string()),
-                    "target_arch" => target_arch = Some(value.trim_matches('"').to_string()),
-                    "target_endian" => target_endian = Some(value.trim_matches('"').to_string()),
-                    "target_pointer_width" => {
-                        target_pointer_width = Some(value.trim_matches('"').to_string())
-                    }
-                    "target_env" => target_env = Some(value.trim_matches('"').to_string()),
-                    "target_vendor" => target_vendor = Some(value.trim_matches('"').to_string()),
-                    "target_has_atomic" => {
-                        target_has_atomic.push(value.trim_matches('"').to_string())
+            let mut parts = entry.splitn(2, '=');
+
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => {
+                    let value = value.trim_matches('"').to_string();
+                    match key {
+                        "target_os" => target_os = Some(value.clone()),
+                        "target_family" => target_family = Some(value.clone()),
+                        "target_arch" => target_arch = Some(value.clone()),
+                        "target_endian" => target_endian = Some(value.clone()),
+                        "target_pointer_width" => target_pointer_width = Some(value.clone()),
+                        "target_env" => target_env = Some(value.clone()),
+                        "target_vendor" => target_vendor = Some(value.clone()),
+                        "target_has_atomic" => target_has_atomic.push(value.clone()),
+                        "target_feature" => target_feature.push(value.clone()),
+                        _ => {}
                     }
-                    "target_feature" => target_feature.push(value.trim_matches('"').to_string()),
-                    _ => {}
+                    key_values.entry(key.to_string()).or_default().push(value);
                 }
+                (Some(atom), None) if !atom.is_empty() => atoms.push(atom.to_string()),
+                _ => {}
             }
         }
 
@@ -120,16 +215,464 @@ impl Cfg {
             target_vendor,
             target_has_atomic,
             target_feature,
+            atoms,
+            key_values,
             _extensible: (),
         })
     }
 }
 
+/// Builder for a customized `rustc --print cfg` invocation.
+///
+/// Obtained via [`Cfg::builder`]. Every setter is chainable; a terminal
+/// [`run`](CfgBuilder::run) spawns `rustc` and returns the parsed [`Cfg`].
+///
+/// ```no_run
+/// use rustc_cfg::Cfg;
+///
+/// let cfg = Cfg::builder()
+///     .target("x86_64-unknown-linux-gnu")
+///     .arg("-Ctarget-feature=+avx2")
+///     .run()
+///     .unwrap();
+/// assert!(cfg.target_feature.iter().any(|f| f == "avx2"));
+/// ```
+pub struct CfgBuilder {
+    rustc: Option<PathBuf>,
+    target: Option<OsString>,
+    args: Vec<OsString>,
+    envs: Vec<(OsString, OsString)>,
+}
+
+impl CfgBuilder {
+    fn new() -> CfgBuilder {
+        CfgBuilder {
+            rustc: None,
+            target: None,
+            args: vec![],
+            envs: vec![],
+        }
+    }
+
+    /// Sets the path to the `rustc` executable to invoke.
+    ///
+    /// Defaults to the `RUSTC` environment variable, or plain `rustc` if unset.
+    pub fn rustc<P>(&mut self, rustc: P) -> &mut Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.rustc = Some(rustc.into());
+        self
+    }
+
+    /// Sets the target: either a triple or a path to a `.json` target
+    /// specification.
+    ///
+    /// The value is forwarded verbatim to `--target`; rustc itself decides
+    /// whether to treat it as a triple or a target-spec file.
+    pub fn target<T>(&mut self, target: T) -> &mut Self
+    where
+        T: Into<OsString>,
+    {
+        self.target = Some(target.into());
+        self
+    }
+
+    /// Adds an extra argument passed to `rustc`, e.g. `-Ctarget-feature=+crt-static`.
+    pub fn arg<S>(&mut self, arg: S) -> &mut Self
+    where
+        S: Into<OsString>,
+    {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Adds several extra arguments passed to `rustc`.
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets an environment variable for the `rustc` process.
+    pub fn env<K, V>(&mut self, key: K, val: V) -> &mut Self
+    where
+        K: Into<OsString>,
+        V: Into<OsString>,
+    {
+        self.envs.push((key.into(), val.into()));
+        self
+    }
+
+    /// Sets several environment variables for the `rustc` process.
+    pub fn envs<I, K, V>(&mut self, envs: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<OsString>,
+        V: Into<OsString>,
+    {
+        self.envs
+            .extend(envs.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    /// Spawns `rustc` with the accumulated configuration and returns the parsed
+    /// [`Cfg`].
+    pub fn run(&self) -> Result<Cfg, failure::Error> {
+        // NOTE Cargo passes RUSTC to build scripts, prefer that over plain `rustc`.
+        let rustc = self.rustc.clone().unwrap_or_else(|| {
+            PathBuf::from(env::var_os("RUSTC").unwrap_or_else(|| OsString::from("rustc")))
+        });
+
+        let mut command = Command::new(rustc);
+        if let Some(ref target) = self.target {
+            command.arg("--target").arg(target);
+        }
+        command.args(&["--print", "cfg"]);
+        command.args(&self.args);
+        for (key, val) in &self.envs {
+            command.env(key, val);
+        }
+
+        let output = command.output()?;
+        if !output.status.success() {
+            return Err(failure::err_msg(String::from_utf8(output.stderr)?));
+        }
+
+        Cfg::parse(&String::from_utf8(output.stdout)?)
+    }
+}
+
+/// A rustc crate type (the argument to `--crate-type`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CrateType {
+    /// An executable (`bin`)
+    Bin,
+    /// A Rust library, format chosen by rustc (`lib`)
+    Lib,
+    /// A Rust static library (`rlib`)
+    Rlib,
+    /// A Rust dynamic library (`dylib`)
+    Dylib,
+    /// A C-compatible dynamic library (`cdylib`)
+    Cdylib,
+    /// A C-compatible static library (`staticlib`)
+    Staticlib,
+    /// A procedural macro library (`proc-macro`)
+    ProcMacro,
+}
+
+const CRATE_TYPES: &[CrateType] = &[
+    CrateType::Bin,
+    CrateType::Lib,
+    CrateType::Rlib,
+    CrateType::Dylib,
+    CrateType::Cdylib,
+    CrateType::Staticlib,
+    CrateType::ProcMacro,
+];
+
+impl CrateType {
+    fn flag(self) -> &'static str {
+        match self {
+            CrateType::Bin => "bin",
+            CrateType::Lib => "lib",
+            CrateType::Rlib => "rlib",
+            CrateType::Dylib => "dylib",
+            CrateType::Cdylib => "cdylib",
+            CrateType::Staticlib => "staticlib",
+            CrateType::ProcMacro => "proc-macro",
+        }
+    }
+}
+
+// Crate name rustc assigns when reading a crate from stdin (`-`).
+const STDIN_CRATE_NAME: &str = "rust_out";
+
+fn file_names(
+    target: &str,
+    crate_type: CrateType,
+) -> Result<Option<(String, String)>, failure::Error> {
+    // NOTE Cargo passes RUSTC to build scripts, prefer that over plain `rustc`.
+    let output = Command::new(env::var("RUSTC").as_ref().map(|s| &**s).unwrap_or("rustc"))
+        .arg("--target")
+        .arg(target)
+        .args(["--crate-type", crate_type.flag()])
+        .args(["--print", "file-names"])
+        .arg("-")
+        .stdin(Stdio::null())
+        .output()?;
+
+    // An unsupported crate type makes rustc bail out; report it as `None`.
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let name = match stdout.lines().find(|line| !line.trim().is_empty()) {
+        Some(name) => name.trim(),
+        None => return Ok(None),
+    };
+
+    // Split the reported filename around the crate name to recover the prefix
+    // and suffix that the target uses for this crate type.
+    match name.find(STDIN_CRATE_NAME) {
+        Some(idx) => {
+            let prefix = name[..idx].to_string();
+            let suffix = name[idx + STDIN_CRATE_NAME.len()..].to_string();
+            Ok(Some((prefix, suffix)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The output filename prefixes and suffixes for each [`CrateType`] on a target,
+/// as returned by [`Cfg::outputs`].
+pub struct Outputs {
+    map: HashMap<CrateType, Option<(String, String)>>,
+}
+
+impl Outputs {
+    /// Returns the `(prefix, suffix)` used for `crate_type`, or `None` if the
+    /// target does not support that crate type.
+    pub fn get(&self, crate_type: CrateType) -> Option<(&str, &str)> {
+        self.map
+            .get(&crate_type)
+            .and_then(|entry| entry.as_ref())
+            .map(|(prefix, suffix)| (&prefix[..], &suffix[..]))
+    }
+}
+
+/// A bare `cfg` name, e.g. the `unix` in `cfg(unix)`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Name(pub String);
+
+/// A parsed `cfg(..)` expression
+///
+/// This mirrors the grammar that rustc and Cargo accept in `--cfg` flags and in
+/// `[target.'cfg(..)']` manifest keys: a predicate is either a bare identifier
+/// (`unix`, `debug_assertions`), a `key = "value"` pair or one of the `all`,
+/// `any` and `not` combinators. Parse one with [`CfgExpr::from_str`] and test it
+/// against a target with [`Cfg::matches`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CfgExpr {
+    /// `all(p, p, ..)` — true when every child matches (an empty list is true)
+    All(Vec<CfgExpr>),
+    /// `any(p, p, ..)` — true when any child matches (an empty list is false)
+    Any(Vec<CfgExpr>),
+    /// `not(p)` — true when the child does not match
+    Not(Box<CfgExpr>),
+    /// a bare identifier like `unix`
+    Value(Name),
+    /// a `key = "value"` pair like `target_arch = "x86_64"`
+    KeyPair(String, String),
+}
+
+impl FromStr for CfgExpr {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<CfgExpr, failure::Error> {
+        let tokens = tokenize(s)?;
+        let mut parser = ExprParser { tokens: &tokens, pos: 0 };
+        let expr = parser.expr()?;
+        if parser.pos != parser.tokens.len() {
+            return Err(failure::err_msg("trailing tokens after `cfg` expression"));
+        }
+        Ok(expr)
+    }
+}
+
+#[derive(PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    OpenParen,
+    CloseParen,
+    Comma,
+    Eq,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, failure::Error> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::OpenParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::CloseParen);
+            }
+            ',' => {
+                chars.next();
+                tokens.push(Token::Comma);
+            }
+            '=' => {
+                chars.next();
+                tokens.push(Token::Eq);
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => value.push(c),
+                        None => return Err(failure::err_msg("unterminated string literal")),
+                    }
+                }
+                tokens.push(Token::Str(value));
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' {
+                        ident.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Ident(ident));
+            }
+            _ => return Err(failure::err_msg(format!("unexpected character `{}`", c))),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct ExprParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> ExprParser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn bump(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.pos);
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> Result<(), failure::Error> {
+        match self.bump() {
+            Some(t) if t == token => Ok(()),
+            _ => Err(failure::err_msg("unbalanced or malformed `cfg` expression")),
+        }
+    }
+
+    fn expr(&mut self) -> Result<CfgExpr, failure::Error> {
+        let ident = match self.bump() {
+            Some(Token::Ident(ident)) => ident.clone(),
+            _ => return Err(failure::err_msg("expected a `cfg` predicate")),
+        };
+
+        match &ident[..] {
+            "cfg" => {
+                self.eat(&Token::OpenParen)?;
+                let expr = self.expr()?;
+                self.eat(&Token::CloseParen)?;
+                Ok(expr)
+            }
+            "all" if self.peek() == Some(&Token::OpenParen) => {
+                Ok(CfgExpr::All(self.list()?))
+            }
+            "any" if self.peek() == Some(&Token::OpenParen) => {
+                Ok(CfgExpr::Any(self.list()?))
+            }
+            "not" if self.peek() == Some(&Token::OpenParen) => {
+                let mut inner = self.list()?;
+                if inner.len() != 1 {
+                    return Err(failure::err_msg("`not` takes exactly one predicate"));
+                }
+                Ok(CfgExpr::Not(Box::new(inner.pop().unwrap())))
+            }
+            _ => {
+                if self.peek() == Some(&Token::Eq) {
+                    self.bump();
+                    match self.bump() {
+                        Some(Token::Str(value)) => {
+                            Ok(CfgExpr::KeyPair(ident, value.clone()))
+                        }
+                        _ => Err(failure::err_msg("expected a quoted value after `=`")),
+                    }
+                } else {
+                    Ok(CfgExpr::Value(Name(ident)))
+                }
+            }
+        }
+    }
+
+    fn list(&mut self) -> Result<Vec<CfgExpr>, failure::Error> {
+        self.eat(&Token::OpenParen)?;
+        let mut exprs = vec![];
+        while self.peek() != Some(&Token::CloseParen) {
+            if self.peek().is_none() {
+                return Err(failure::err_msg("unbalanced parentheses in `cfg` expression"));
+            }
+            exprs.push(self.expr()?);
+            match self.peek() {
+                Some(&Token::Comma) => {
+                    self.bump();
+                }
+                _ => break,
+            }
+        }
+        self.eat(&Token::CloseParen)?;
+        Ok(exprs)
+    }
+}
+
+impl Cfg {
+    /// Returns `true` if this target satisfies the `cfg` expression `expr`.
+    ///
+    /// Bare names are matched against the target's `cfg` atoms, key/value pairs
+    /// against its reported keys (a multi-valued key like `target_feature` or
+    /// `target_has_atomic` matches when any of its values is the requested one),
+    /// and `all`/`any`/`not` combine the results as expected.
+    pub fn matches(&self, expr: &CfgExpr) -> bool {
+        match *expr {
+            CfgExpr::All(ref exprs) => exprs.iter().all(|e| self.matches(e)),
+            CfgExpr::Any(ref exprs) => exprs.iter().any(|e| self.matches(e)),
+            CfgExpr::Not(ref expr) => !self.matches(expr),
+            CfgExpr::Value(Name(ref name)) => self.has_atom(name),
+            CfgExpr::KeyPair(ref key, ref value) => self.has_key_value(key, value),
+        }
+    }
+
+    fn has_atom(&self, name: &str) -> bool {
+        self.atoms.iter().any(|a| a == name)
+    }
+
+    fn has_key_value(&self, key: &str, value: &str) -> bool {
+        self.key_values
+            .get(key)
+            .map(|values| values.iter().any(|v| v == value))
+            .unwrap_or(false)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::process::Command;
+    use std::str::FromStr;
 
-    use super::Cfg;
+    use super::{Cfg, CfgExpr, Name};
 
     #[test]
     fn all() {
@@ -146,4 +689,30 @@ mod test {
             println!("{}\n\t{:?}\n", target, Cfg::of(target));
         }
     }
+
+    #[test]
+    fn parse_expr() {
+        assert_eq!(
+            CfgExpr::from_str("unix").unwrap(),
+            CfgExpr::Value(Name("unix".to_string()))
+        );
+        assert_eq!(
+            CfgExpr::from_str(r#"target_arch = "x86_64""#).unwrap(),
+            CfgExpr::KeyPair("target_arch".to_string(), "x86_64".to_string())
+        );
+        assert_eq!(
+            CfgExpr::from_str(r#"cfg(all(unix, target_arch = "x86_64"))"#).unwrap(),
+            CfgExpr::All(vec![
+                CfgExpr::Value(Name("unix".to_string())),
+                CfgExpr::KeyPair("target_arch".to_string(), "x86_64".to_string()),
+            ])
+        );
+        assert_eq!(
+            CfgExpr::from_str("not(windows)").unwrap(),
+            CfgExpr::Not(Box::new(CfgExpr::Value(Name("windows".to_string()))))
+        );
+
+        assert!(CfgExpr::from_str("all(unix").is_err());
+        assert!(CfgExpr::from_str("not(unix, windows)").is_err());
+    }
 }